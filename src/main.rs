@@ -1,6 +1,6 @@
 use actix_cors::Cors;
 use actix_web::{
-    get, http::header, middleware::Compress, web::{Query}, App, HttpRequest, HttpResponse, HttpServer, Responder,
+    get, options, http::header, middleware::Compress, web::{Query}, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use actix_web::body::BodyStream;
 use once_cell::sync::Lazy;
@@ -9,7 +9,9 @@ use reqwest::{
     Client,
 };
 use serde::Deserialize;
-use std::{collections::{HashMap, HashSet}, time::Duration};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{collections::{HashMap, HashSet}, net::IpAddr, time::Duration};
 use url::Url;
 use futures_util::TryStreamExt;
 use regex::Regex;
@@ -38,6 +40,116 @@ static URI_REGEX: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r#"(?i)(URI|URL)="([^"]+)""#).unwrap()
 });
 
+// Reduces a fully-qualified host to its registrable base domain, e.g.
+// `cdn.foo.example.com` -> `example.com`, so the allowlist can be expressed
+// in terms of the domains operators actually own.
+static DOMAIN_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:[a-z\d.-]*\.)?([a-z\d-]*\.[a-z\d-]*)$").unwrap()
+});
+
+// Upstream domains the proxy is permitted to fetch from, loaded from the
+// comma-separated `ALLOWED_DOMAINS` env var. When empty the allowlist is
+// disabled and any (non-private) host is accepted.
+static ALLOWED_DOMAINS: Lazy<HashSet<String>> = Lazy::new(|| {
+    env::var("ALLOWED_DOMAINS")
+        .map(|v| {
+            v.split(',')
+                .map(|d| d.trim().to_lowercase())
+                .filter(|d| !d.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+});
+
+// Reject hosts that point at the loopback, unspecified, or private ranges so
+// the proxy can't be pointed at internal services (SSRF).
+fn is_private_host(host: &str) -> bool {
+    // `localhost` never parses as an IP but must still be blocked.
+    if host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        Ok(IpAddr::V6(ip)) => {
+            // Unwrap IPv4-mapped addresses (e.g. ::ffff:127.0.0.1) and apply
+            // the V4 rules, then fall back to the V6 private ranges.
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified();
+            }
+            let seg = ip.segments();
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (seg[0] & 0xfe00) == 0xfc00 // fc00::/7 unique-local
+                || (seg[0] & 0xffc0) == 0xfe80 // fe80::/10 link-local
+        }
+        Err(_) => false,
+    }
+}
+
+// Enforce the SSRF/open-proxy policy against a resolved target URL: block
+// private IP literals outright, then require the registrable base domain to
+// be present in `ALLOWED_DOMAINS` whenever that allowlist is configured.
+fn is_url_allowed(url: &Url) -> bool {
+    let host = match url.host_str() {
+        Some(h) => h.to_lowercase(),
+        None => return false,
+    };
+
+    if is_private_host(&host) {
+        return false;
+    }
+
+    if ALLOWED_DOMAINS.is_empty() {
+        return true;
+    }
+
+    let base = DOMAIN_REGEX
+        .captures(&host)
+        .map(|caps| caps[1].to_string())
+        .unwrap_or(host);
+
+    ALLOWED_DOMAINS.contains(&base)
+}
+
+// When set, proxy URLs must carry a matching `qhash` signature, stopping
+// third parties from using the proxy for their own traffic.
+static PROXY_SECRET: Lazy<Option<String>> = Lazy::new(|| {
+    env::var("PROXY_SECRET").ok().filter(|s| !s.is_empty())
+});
+
+// HMAC-SHA256 over the canonical `url` + `headers` message, keyed by the
+// secret, truncated to the first 8 bytes (16 hex chars). Cheap enough to run
+// synchronously on the request path.
+fn compute_qhash(url: &str, headers: &str, secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(url.as_bytes());
+    mac.update(headers.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Compare two signatures without leaking timing information about how many
+// leading bytes matched.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
+
 // Static CORS enable flag
 static ENABLE_CORS: Lazy<bool> = Lazy::new(|| {
     env::var("ENABLE_CORS")
@@ -45,12 +157,44 @@ static ENABLE_CORS: Lazy<bool> = Lazy::new(|| {
         .unwrap_or(false)
 });
 
-// Static allowed origins for CORS
-static ALLOWED_ORIGINS: Lazy<[&str; 3]> = Lazy::new(|| [
-    "http://localhost:5173",
-    "http://localhost:3000",
-    "http://aniwave.at",
-]);
+// A single allowed-origin rule: either an exact origin or a wildcard
+// subdomain pattern compiled to a regex.
+enum OriginPattern {
+    Exact(String),
+    Pattern(Regex),
+}
+
+// Allowed CORS origins, loaded from the comma-separated `ALLOWED_ORIGINS`
+// env var at startup. Entries containing `*` (e.g. `https://*.aniwave.at`)
+// are compiled to a subdomain-matching regex.
+static ALLOWED_ORIGINS: Lazy<Vec<OriginPattern>> = Lazy::new(|| {
+    env::var("ALLOWED_ORIGINS")
+        .unwrap_or_else(|_| {
+            "http://localhost:5173,http://localhost:3000,http://aniwave.at".to_string()
+        })
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|origin| {
+            if origin.contains('*') {
+                let pattern = format!("^{}$", regex::escape(origin).replace("\\*", "[a-z0-9-]+"));
+                Regex::new(&pattern)
+                    .map(OriginPattern::Pattern)
+                    .unwrap_or_else(|_| OriginPattern::Exact(origin.to_string()))
+            } else {
+                OriginPattern::Exact(origin.to_string())
+            }
+        })
+        .collect()
+});
+
+// Whether an origin string satisfies any configured allow rule.
+fn origin_matches(origin: &str) -> bool {
+    ALLOWED_ORIGINS.iter().any(|rule| match rule {
+        OriginPattern::Exact(exact) => exact == origin,
+        OriginPattern::Pattern(re) => re.is_match(origin),
+    })
+}
 
 // Query parameters structure
 #[derive(Deserialize)]
@@ -58,6 +202,89 @@ struct QueryParams {
     url: String,
     headers: Option<String>,
     origin: Option<String>,
+    qhash: Option<String>,
+}
+
+// Optional on-the-fly image transcoding. Only compiled when a target encoder
+// feature is enabled, and only active when `ENABLE_IMAGE_TRANSCODE=true`.
+#[cfg(any(feature = "webp", feature = "avif"))]
+static ENABLE_IMAGE_TRANSCODE: Lazy<bool> = Lazy::new(|| {
+    env::var("ENABLE_IMAGE_TRANSCODE")
+        .map(|v| v.to_lowercase() == "true")
+        .unwrap_or(false)
+});
+
+#[cfg(any(feature = "webp", feature = "avif"))]
+#[derive(Clone, Copy)]
+enum ImageTarget {
+    #[cfg(feature = "webp")]
+    Webp,
+    #[cfg(feature = "avif")]
+    Avif,
+}
+
+// Pick a re-encode target from the upstream type and the client's `Accept`
+// header, preferring AVIF over WebP and honoring the compiled-in encoders.
+#[cfg(any(feature = "webp", feature = "avif"))]
+fn select_image_target(content_type: &str, accept: &str) -> Option<ImageTarget> {
+    if !content_type.starts_with("image/") {
+        return None;
+    }
+    #[cfg(feature = "avif")]
+    if accept.contains("image/avif") {
+        return Some(ImageTarget::Avif);
+    }
+    #[cfg(feature = "webp")]
+    if accept.contains("image/webp") {
+        return Some(ImageTarget::Webp);
+    }
+    None
+}
+
+// Decode and re-encode an image to the requested format. Runs on a blocking
+// thread; returns the encoded bytes and the new MIME type.
+#[cfg(any(feature = "webp", feature = "avif"))]
+fn encode_image(data: &[u8], target: ImageTarget) -> Result<(Vec<u8>, &'static str), image::ImageError> {
+    let img = image::load_from_memory(data)?;
+    let mut out = std::io::Cursor::new(Vec::new());
+    let content_type = match target {
+        #[cfg(feature = "webp")]
+        ImageTarget::Webp => {
+            img.write_to(&mut out, image::ImageFormat::WebP)?;
+            "image/webp"
+        }
+        #[cfg(feature = "avif")]
+        ImageTarget::Avif => {
+            img.write_to(&mut out, image::ImageFormat::Avif)?;
+            "image/avif"
+        }
+    };
+    Ok((out.into_inner(), content_type))
+}
+
+// Decide whether an upstream response header should be forwarded to the
+// client. Drops hop-by-hop headers, CORS headers we set ourselves, and
+// framing headers that no longer apply once we rebuild/re-stream the body.
+fn is_header_allowed(name: &str) -> bool {
+    let name = name.to_lowercase();
+    if name.starts_with("access-control-") {
+        return false;
+    }
+    !matches!(
+        name.as_str(),
+        "host"
+            | "set-cookie"
+            | "content-length"
+            | "alt-svc"
+            | "connection"
+            | "keep-alive"
+            | "transfer-encoding"
+            | "te"
+            | "trailer"
+            | "upgrade"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+    )
 }
 
 // Resolve relative or absolute URLs
@@ -77,22 +304,53 @@ fn is_allowed_origin(req: &HttpRequest) -> bool {
 
     // Check Origin header
     if let Some(origin) = req.headers().get(header::ORIGIN) {
-        if let Ok(origin_str) = origin.to_str() {
-            return ALLOWED_ORIGINS.contains(&origin_str);
-        }
-        return false;
+        return origin.to_str().map(origin_matches).unwrap_or(false);
     }
 
-    // Check Referer as fallback
+    // Check Referer as fallback, matching on its derived origin
     if let Some(referer) = req.headers().get(header::REFERER) {
         if let Ok(referer_str) = referer.to_str() {
-            return ALLOWED_ORIGINS.iter().any(|allowed| referer_str.starts_with(allowed));
+            if let Ok(url) = Url::parse(referer_str) {
+                return origin_matches(url.origin().ascii_serialization().as_str());
+            }
         }
     }
 
     false
 }
 
+// Answer CORS preflight requests explicitly so credentialed `OPTIONS`
+// requests are handled by the app rather than rejected.
+//
+// Note: when `ENABLE_CORS` is true the `actix-cors` middleware intercepts
+// `OPTIONS` preflight and responds before the router dispatches, so this
+// handler is reached only on the permissive (CORS-disabled) path. It is kept
+// so preflight is answered consistently in both configurations.
+#[options("/")]
+async fn preflight(req: HttpRequest) -> impl Responder {
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|h| h.to_str().ok());
+
+    let allow_origin = match origin {
+        // CORS disabled: stay permissive, echoing the origin or falling back to `*`.
+        _ if !*ENABLE_CORS => origin.unwrap_or("*").to_string(),
+        Some(o) if origin_matches(o) => o.to_string(),
+        _ => return HttpResponse::Forbidden().finish(),
+    };
+
+    HttpResponse::NoContent()
+        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin))
+        .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS"))
+        .insert_header((
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            "Authorization, Accept, Origin, Range",
+        ))
+        .insert_header((header::ACCESS_CONTROL_MAX_AGE, "3600"))
+        .finish()
+}
+
 #[get("/")]
 async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
     // Check origin before processing request
@@ -120,6 +378,20 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
         Err(_) => return HttpResponse::BadRequest().body("Invalid URL format"),
     };
 
+    // Enforce the upstream allowlist / SSRF policy before touching the network
+    if !is_url_allowed(&target_url) {
+        return HttpResponse::Forbidden().body("Target host not allowed");
+    }
+
+    // Require a valid request signature when signing is enabled
+    if let Some(secret) = PROXY_SECRET.as_deref() {
+        let expected = compute_qhash(&query.url, query.headers.as_deref().unwrap_or(""), secret);
+        let provided = query.qhash.as_deref().unwrap_or("");
+        if !constant_time_eq(provided, &expected) {
+            return HttpResponse::Forbidden().body("Invalid or missing qhash");
+        }
+    }
+
     // Build headers
     let mut headers = HeaderMap::new();
     if let Some(header_json) = &query.headers {
@@ -174,6 +446,15 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
         .unwrap_or("")
         .to_string();
 
+    // Snapshot the upstream headers we intend to forward before the body is
+    // consumed by `text()`/`bytes_stream()` below.
+    let forwarded: Vec<(HeaderName, HeaderValue)> = resp
+        .headers()
+        .iter()
+        .filter(|(name, _)| is_header_allowed(name.as_str()))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+
     // Check if response is an m3u8 playlist
     let is_m3u8 = target_url.path().ends_with(".m3u8")
         || M3U8_MIME_TYPES.iter().any(|&mime| content_type.contains(mime));
@@ -204,10 +485,17 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
                             Ok(url) => url,
                             Err(_) => return line.to_string(),
                         };
+                        if !is_url_allowed(&resolved) {
+                            return line.to_string();
+                        }
                         let mut new_q = format!("url={}", urlencoding::encode(resolved.as_str()));
                         if let Some(h) = &query.headers {
                             new_q.push_str(&format!("&headers={}", h));
                         }
+                        if let Some(secret) = PROXY_SECRET.as_deref() {
+                            let qh = compute_qhash(resolved.as_str(), query.headers.as_deref().unwrap_or(""), secret);
+                            new_q.push_str(&format!("&qhash={}", qh));
+                        }
                         return format!("#EXT-X-MAP:URI=\"/?{}\"", new_q);
                     }
 
@@ -220,10 +508,17 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
                                 Ok(url) => url,
                                 Err(_) => return line.to_string(),
                             };
+                            if !is_url_allowed(&resolved) {
+                                return line.to_string();
+                            }
                             let mut new_q = format!("url={}", urlencoding::encode(resolved.as_str()));
                             if let Some(h) = &query.headers {
                                 new_q.push_str(&format!("&headers={}", h));
                             }
+                            if let Some(secret) = PROXY_SECRET.as_deref() {
+                                let qh = compute_qhash(resolved.as_str(), query.headers.as_deref().unwrap_or(""), secret);
+                                new_q.push_str(&format!("&qhash={}", qh));
+                            }
                             new_line = URI_REGEX
                                 .replace(&new_line, format!(r#"{}="/?{}""#, key, new_q))
                                 .to_string();
@@ -238,44 +533,123 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
                     Ok(url) => url,
                     Err(_) => return line.to_string(),
                 };
+                if !is_url_allowed(&resolved) {
+                    return line.to_string();
+                }
                 let mut new_q = format!("url={}", urlencoding::encode(resolved.as_str()));
                 if let Some(h) = &query.headers {
                     new_q.push_str(&format!("&headers={}", h));
                 }
+                if let Some(secret) = PROXY_SECRET.as_deref() {
+                    let qh = compute_qhash(resolved.as_str(), query.headers.as_deref().unwrap_or(""), secret);
+                    new_q.push_str(&format!("&qhash={}", qh));
+                }
                 format!("/?{}", new_q)
             })
             .collect();
 
-        return HttpResponse::Ok()
-            .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin))
-            .insert_header(("Content-Type", "application/vnd.apple.mpegurl"))
-            .body(lines.join("\n"));
+        let mut builder = HttpResponse::Ok();
+        builder.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin));
+        for (name, value) in &forwarded {
+            // We rewrite the playlist body, so force our own Content-Type.
+            if name.as_str().eq_ignore_ascii_case("content-type") {
+                continue;
+            }
+            builder.insert_header((name.clone(), value.clone()));
+        }
+        builder.insert_header(("Content-Type", "application/vnd.apple.mpegurl"));
+        return builder.body(lines.join("\n"));
+    }
+
+    // Optionally transcode proxied images to a lighter format the client
+    // advertised support for, sending the original bytes if decoding or
+    // re-encoding fails. A failure to read the upstream body returns 500.
+    #[cfg(any(feature = "webp", feature = "avif"))]
+    {
+        let accept = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if *ENABLE_IMAGE_TRANSCODE {
+            if let Some(target) = select_image_target(&content_type, accept) {
+                let body = match resp.bytes().await {
+                    Ok(b) => b,
+                    Err(e) => {
+                        return HttpResponse::InternalServerError()
+                            .body(format!("Failed to read image body: {}", e))
+                    }
+                };
+                let raw = body.to_vec();
+                let encoded = tokio::task::spawn_blocking(move || encode_image(&raw, target))
+                    .await
+                    .ok()
+                    .and_then(|r| r.ok());
+
+                let mut builder = HttpResponse::build(status);
+                builder.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin));
+                for (name, value) in &forwarded {
+                    if name.as_str().eq_ignore_ascii_case("content-type") {
+                        continue;
+                    }
+                    builder.insert_header((name.clone(), value.clone()));
+                }
+                return match encoded {
+                    Some((out, new_type)) => {
+                        builder.insert_header(("Content-Type", new_type));
+                        builder.body(out)
+                    }
+                    None => {
+                        builder.insert_header(("Content-Type", content_type.clone()));
+                        builder.body(body)
+                    }
+                };
+            }
+        }
     }
 
     // Stream non-m3u8 resources
     let stream = resp
         .bytes_stream()
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
-    HttpResponse::build(status)
-        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin))
-        .insert_header(("Content-Type", content_type))
-        .body(BodyStream::new(stream))
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin));
+    let mut has_content_type = false;
+    for (name, value) in &forwarded {
+        if name.as_str().eq_ignore_ascii_case("content-type") {
+            has_content_type = true;
+        }
+        builder.insert_header((name.clone(), value.clone()));
+    }
+    // If upstream sent no (readable) Content-Type, `content_type` is empty and
+    // an empty Content-Type header is sent.
+    if !has_content_type {
+        builder.insert_header(("Content-Type", content_type));
+    }
+    builder.body(BodyStream::new(stream))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenvy::dotenv().ok(); 
     println!("Server running at: http://127.0.0.1:8080");
-    println!("CORS enabled: {}, Allowed origins: {:?}", *ENABLE_CORS, *ALLOWED_ORIGINS);
+    println!("CORS enabled: {}, Allowed origin rules: {}", *ENABLE_CORS, ALLOWED_ORIGINS.len());
+
+    // Allow overriding the worker count; default to one per logical CPU.
+    let workers = env::var("WORKERS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(num_cpus::get);
 
-    HttpServer::new(|| {
+    let server = HttpServer::new(|| {
         let cors = if *ENABLE_CORS {
             Cors::default()
-                .allowed_origin("http://localhost:5173")
-                .allowed_origin("http://localhost:3000")
-                .allowed_origin("http://aniwave.at")
-                .allowed_methods(vec!["GET"])
-                .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT, header::ORIGIN])
+                .allowed_origin_fn(|origin, _req| {
+                    origin.to_str().map(origin_matches).unwrap_or(false)
+                })
+                .allowed_methods(vec!["GET", "OPTIONS"])
+                .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT, header::ORIGIN, header::RANGE])
                 .max_age(3600)
                 .supports_credentials()
         } else {
@@ -285,10 +659,27 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .wrap(Compress::default())
+            .service(preflight)
             .service(m3u8_proxy)
     })
-    .workers(num_cpus::get())
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
+    .workers(workers);
+
+    // Bind to a Unix-domain socket when requested (handy behind nginx/caddy),
+    // otherwise to the TCP address from `BIND`.
+    let use_uds = env::var("UDS")
+        .or_else(|_| env::var("BIND_UNIX"))
+        .map(|v| v.to_lowercase() == "true")
+        .unwrap_or(false);
+
+    let server = if use_uds {
+        let path = env::var("BIND").unwrap_or_else(|_| "/tmp/m3u8-proxy.sock".to_string());
+        println!("Binding to Unix socket: {}", path);
+        server.bind_uds(path)?
+    } else {
+        let addr = env::var("BIND").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
+        println!("Binding to TCP address: {}", addr);
+        server.bind(addr)?
+    };
+
+    server.run().await
 }
\ No newline at end of file